@@ -0,0 +1,207 @@
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rustls::{Certificate, ClientConnection, ProtocolVersion, SupportedCipherSuite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::common::{handshake::IoSession, Stream, TlsState};
+
+/// A wrapper around an underlying raw stream which implements the TLS protocol
+/// as a client.
+pub struct TlsStream<IO> {
+    pub(crate) io: IO,
+    pub(crate) session: ClientConnection,
+    pub(crate) state: TlsState,
+    pub(crate) lazy: bool,
+    pub(crate) coalesce_limit: Option<usize>,
+    pub(crate) write_buf: Vec<u8>,
+}
+
+impl<IO> TlsStream<IO> {
+    #[inline]
+    pub fn get_ref(&self) -> (&IO, &ClientConnection) {
+        (&self.io, &self.session)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> (&mut IO, &mut ClientConnection) {
+        (&mut self.io, &mut self.session)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> (IO, ClientConnection) {
+        (self.io, self.session)
+    }
+
+    /// Coalesce small application writes into a single TLS record until `limit`
+    /// bytes have accumulated. Passing `None` (the default) restores the
+    /// one-record-per-write behaviour; the bound composes with the session's
+    /// own `set_buffer_limit` backpressure.
+    pub fn set_coalesce_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        // A zero bound is treated as "disabled" (see `Stream::set_coalesce_limit`).
+        self.coalesce_limit = limit.filter(|&n| n > 0);
+        self
+    }
+
+    /// The protocol negotiated via ALPN, once the handshake has completed.
+    #[inline]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.session.alpn_protocol()
+    }
+
+    /// The negotiated TLS protocol version, once the handshake has completed.
+    #[inline]
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.session.protocol_version()
+    }
+
+    /// The negotiated cipher suite, once the handshake has completed.
+    #[inline]
+    pub fn negotiated_cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        self.session.negotiated_cipher_suite()
+    }
+
+    /// The certificate chain the server presented, if any.
+    #[inline]
+    pub fn peer_certificates(&self) -> Option<&[Certificate]> {
+        self.session.peer_certificates()
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Builds the inner [`Stream`] -- carrying over the deferred-handshake and
+    /// coalescing state that lives on this stream across polls -- runs `f`
+    /// against it, then restores that state (and records a completed deferred
+    /// handshake as a `Handshaking -> Stream` transition).
+    fn poll_io<R>(
+        &mut self,
+        f: impl FnOnce(Pin<&mut Stream<'_, IO, ClientConnection>>) -> Poll<io::Result<R>>,
+    ) -> Poll<io::Result<R>> {
+        let mut stream = if self.lazy {
+            Stream::new_lazy(&mut self.io, &mut self.session)
+        } else {
+            Stream::new(&mut self.io, &mut self.session)
+        }
+        .set_eof(!self.state.readable())
+        .set_coalesce_limit(self.coalesce_limit);
+        mem::swap(&mut self.write_buf, &mut stream.write_buf);
+
+        let output = f(stream.as_mut_pin());
+
+        mem::swap(&mut self.write_buf, &mut stream.write_buf);
+        if !stream.lazy && self.state == TlsState::Handshaking {
+            self.lazy = false;
+            self.state = TlsState::Stream;
+        }
+
+        output
+    }
+}
+
+impl<IO> AsyncRead for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.state {
+            TlsState::ReadShutdown | TlsState::FullyShutdown => return Poll::Ready(Ok(())),
+            _ => (),
+        }
+
+        let mut stream = if this.lazy {
+            Stream::new_lazy(&mut this.io, &mut this.session)
+        } else {
+            Stream::new(&mut this.io, &mut this.session)
+        }
+        .set_eof(!this.state.readable());
+
+        let prev = buf.remaining();
+        let output = stream.as_mut_pin().poll_read(cx, buf);
+        let eof = stream.eof;
+        let handshake_done = !stream.lazy;
+        drop(stream);
+
+        if handshake_done && this.state == TlsState::Handshaking {
+            this.lazy = false;
+            this.state = TlsState::Stream;
+        }
+
+        match output {
+            Poll::Ready(Ok(())) => {
+                // A zero-byte read into a non-empty buffer is the peer's
+                // close-notify.
+                if eof || (prev != 0 && buf.remaining() == prev) {
+                    this.state.shutdown_read();
+                }
+
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                this.state.shutdown_read();
+                Poll::Ready(Ok(()))
+            }
+            output => output,
+        }
+    }
+}
+
+impl<IO> AsyncWrite for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(|stream| stream.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(|stream| stream.poll_write_vectored(cx, bufs))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(|stream| stream.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.state.writeable() {
+            self.session.send_close_notify();
+            self.state.shutdown_write();
+        }
+
+        self.get_mut().poll_io(|stream| stream.poll_shutdown(cx))
+    }
+}
+
+impl<IO> IoSession for TlsStream<IO> {
+    type Io = IO;
+    type Session = ClientConnection;
+
+    #[inline]
+    fn skip_handshake(&self) -> bool {
+        self.lazy
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> (&mut TlsState, &mut Self::Io, &mut Self::Session) {
+        (&mut self.state, &mut self.io, &mut self.session)
+    }
+}
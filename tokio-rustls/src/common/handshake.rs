@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rustls::{ConnectionCommon, SideData};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{Stream, TlsState};
+
+/// A stream that is partway through its TLS handshake.
+///
+/// Both the client and server stream types implement this so that the shared
+/// [`MidHandshake`] future can drive either of them to completion without
+/// caring which side it is on.
+pub(crate) trait IoSession {
+    type Io;
+    type Session;
+
+    /// Whether the handshake should be skipped entirely and deferred to the
+    /// first read or write instead of being run eagerly.
+    fn skip_handshake(&self) -> bool;
+
+    /// Split borrows of the state, transport and session for the driver.
+    fn get_mut(&mut self) -> (&mut TlsState, &mut Self::Io, &mut Self::Session);
+}
+
+/// A future that drives [`Stream::handshake`] to completion and resolves to the
+/// fully handshaked stream. When [`IoSession::skip_handshake`] is true it
+/// resolves at once and the handshake runs on the first read or write.
+pub(crate) enum MidHandshake<IS> {
+    Handshaking(IS),
+    End,
+    Error(io::Error),
+}
+
+impl<IS, SD> Future for MidHandshake<IS>
+where
+    IS: IoSession + Unpin,
+    IS::Io: AsyncRead + AsyncWrite + Unpin,
+    IS::Session: DerefMut + Deref<Target = ConnectionCommon<SD>> + Unpin,
+    SD: SideData,
+{
+    type Output = io::Result<IS>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut stream = match mem::replace(this, MidHandshake::End) {
+            MidHandshake::Handshaking(stream) => stream,
+            MidHandshake::Error(err) => return Poll::Ready(Err(err)),
+            // We had an error downstream, or were polled after completion.
+            MidHandshake::End => panic!("unexpected polling after handshake"),
+        };
+
+        if !stream.skip_handshake() {
+            let (state, io, session) = stream.get_mut();
+            let mut tls_stream = Stream::new(io, session).set_eof(!state.readable());
+
+            macro_rules! try_poll {
+                ( $e:expr ) => {
+                    match $e {
+                        Poll::Ready(Ok(_)) => (),
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => {
+                            *this = MidHandshake::Handshaking(stream);
+                            return Poll::Pending;
+                        }
+                    }
+                };
+            }
+
+            while tls_stream.session.is_handshaking() {
+                try_poll!(tls_stream.handshake(cx));
+            }
+
+            try_poll!(Pin::new(&mut tls_stream).poll_flush(cx));
+
+            *state = TlsState::Stream;
+        }
+
+        Poll::Ready(Ok(stream))
+    }
+}
@@ -0,0 +1,616 @@
+use std::io::{self, IoSlice, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rustls::{ConnectionCommon, ServerConfig, ServerConnection, SideData};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub(crate) mod handshake;
+
+#[cfg(test)]
+mod test_stream;
+
+/// The lifecycle of a TLS stream, from the handshake through an orderly
+/// close in each direction.
+///
+/// Replacing the loose `set_eof`/`poll_shutdown` booleans with an explicit
+/// state machine makes the close-notify logic a set of transitions: reads are
+/// only attempted while the read half is still live, a close-notify is sent at
+/// most once, and a stream is only `FullyShutdown` once both halves have been
+/// closed. Writes are not gated on the state.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TlsState {
+    /// The handshake has not completed yet.
+    Handshaking,
+    /// The handshake is done and the stream carries application data.
+    Stream,
+    /// The read half has seen the peer's close-notify, or the transport has
+    /// reached EOF.
+    ReadShutdown,
+    /// The write half has sent a close-notify.
+    WriteShutdown,
+    /// Both halves have been closed.
+    FullyShutdown,
+}
+
+impl TlsState {
+    #[inline]
+    pub fn shutdown_read(&mut self) {
+        match *self {
+            TlsState::WriteShutdown | TlsState::FullyShutdown => *self = TlsState::FullyShutdown,
+            _ => *self = TlsState::ReadShutdown,
+        }
+    }
+
+    #[inline]
+    pub fn shutdown_write(&mut self) {
+        match *self {
+            TlsState::ReadShutdown | TlsState::FullyShutdown => *self = TlsState::FullyShutdown,
+            _ => *self = TlsState::WriteShutdown,
+        }
+    }
+
+    #[inline]
+    pub fn writeable(&self) -> bool {
+        !matches!(*self, TlsState::WriteShutdown | TlsState::FullyShutdown)
+    }
+
+    #[inline]
+    pub fn readable(&self) -> bool {
+        !matches!(*self, TlsState::ReadShutdown | TlsState::FullyShutdown)
+    }
+}
+
+pub struct Stream<'a, IO, C> {
+    pub io: &'a mut IO,
+    pub session: &'a mut C,
+    pub eof: bool,
+    /// When set, the handshake is postponed until the first `poll_read`/
+    /// `poll_write` rather than being run eagerly.
+    pub lazy: bool,
+    /// When set, small application writes are gathered into this buffer and
+    /// emitted as a single TLS record once it reaches the configured bound,
+    /// rather than one record per `poll_write`.
+    pub(crate) coalesce_limit: Option<usize>,
+    pub(crate) write_buf: Vec<u8>,
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite + Unpin, C, SD> Stream<'a, IO, C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+    SD: SideData,
+{
+    pub fn new(io: &'a mut IO, session: &'a mut C) -> Self {
+        Stream {
+            io,
+            session,
+            // The state so far is only used to detect EOF, so either Stream
+            // or EarlyData state should both be all right.
+            eof: false,
+            lazy: false,
+            coalesce_limit: None,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Like [`new`](Self::new), but defers the TLS handshake until the first
+    /// read or write. Servers that open many connections which never send data
+    /// can avoid allocating handshake buffers up front this way.
+    pub fn new_lazy(io: &'a mut IO, session: &'a mut C) -> Self {
+        Stream {
+            io,
+            session,
+            eof: false,
+            lazy: true,
+            coalesce_limit: None,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Coalesce application writes into a single TLS record until `limit` bytes
+    /// have accumulated, then flush. Passing `None` (the default) restores the
+    /// one-record-per-write behaviour. The bound composes with the session's
+    /// own `set_buffer_limit` backpressure: a full coalescing buffer that
+    /// cannot be drained yields `Pending`.
+    pub fn set_coalesce_limit(mut self, limit: Option<usize>) -> Self {
+        // A zero bound would coalesce nothing yet still enter the buffered
+        // path; treat it as "disabled" so writes never stall on an empty
+        // buffer that can never reach the threshold.
+        self.coalesce_limit = limit.filter(|&n| n > 0);
+        self
+    }
+
+    /// Drives the handshake to completion on a deferred stream, returning
+    /// `Pending` (propagating any `WouldBlock`) exactly as a post-handshake
+    /// read or write would until the transport is ready.
+    fn poll_lazy_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.lazy && self.session.is_handshaking() {
+            ready!(self.handshake(cx))?;
+        }
+        self.lazy = false;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Drains the coalescing buffer into one or more TLS records and pushes
+    /// them out to the transport.
+    fn flush_coalesced(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            match self.session.writer().write(&self.write_buf) {
+                // The session plaintext buffer is full (`set_buffer_limit`);
+                // drive the transport to drain it -- and register a waker --
+                // before yielding, rather than parking with no wakeup armed.
+                Ok(0) => match self.write_io(cx) {
+                    Poll::Ready(Ok(0)) | Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(_)) => continue,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                },
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+
+            while self.session.wants_write() {
+                match self.write_io(cx) {
+                    Poll::Ready(Ok(0)) | Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(_)) => (),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                }
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Buffers `buf`, emitting a record only once the coalescing bound is hit.
+    fn poll_write_coalesced(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        limit: usize,
+    ) -> Poll<io::Result<usize>> {
+        // Make room if the buffer is already full, applying backpressure when
+        // it cannot be drained.
+        if self.write_buf.len() >= limit {
+            ready!(self.flush_coalesced(cx))?;
+        }
+
+        // `set_coalesce_limit` normalises `Some(0)` away, so a flushed buffer
+        // always leaves room; guard defensively rather than parking with no
+        // wakeup armed.
+        debug_assert!(limit > 0);
+        let room = limit.saturating_sub(self.write_buf.len());
+        if room == 0 {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let take = room.min(buf.len());
+        self.write_buf.extend_from_slice(&buf[..take]);
+
+        // Emit the coalesced record once the bound is reached; a `Pending`
+        // drain is fine here since the bytes are already buffered.
+        if self.write_buf.len() >= limit {
+            if let Poll::Ready(Err(err)) = self.flush_coalesced(cx) {
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        Poll::Ready(Ok(take))
+    }
+
+    pub fn set_eof(mut self, eof: bool) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn as_mut_pin(&mut self) -> Pin<&mut Self> {
+        Pin::new(self)
+    }
+
+    pub fn read_io(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let mut reader = SyncReadAdapter { io: self.io, cx };
+
+        let n = match self.session.read_tls(&mut reader) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+
+        let stats = self.session.process_new_packets().map_err(|err| {
+            // In case we have an alert to send describing this error,
+            // try a last-gasp write -- but don't predate the primary
+            // error.
+            let _ = self.write_io(cx);
+
+            io::Error::new(io::ErrorKind::InvalidData, err)
+        })?;
+
+        if stats.peer_has_closed() && self.session.is_handshaking() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "tls handshake alert",
+            )));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    pub fn write_io(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let mut writer = SyncWriteAdapter { io: self.io, cx };
+
+        match self.session.write_tls(&mut writer) {
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    pub fn handshake(&mut self, cx: &mut Context) -> Poll<io::Result<(usize, usize)>> {
+        let mut wrlen = 0;
+        let mut rdlen = 0;
+
+        loop {
+            let mut write_would_block = false;
+            let mut read_would_block = false;
+
+            while self.session.wants_write() {
+                match self.write_io(cx) {
+                    Poll::Ready(Ok(n)) => wrlen += n,
+                    Poll::Pending => {
+                        write_would_block = true;
+                        break;
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                }
+            }
+
+            while !self.eof && self.session.wants_read() {
+                match self.read_io(cx) {
+                    Poll::Ready(Ok(0)) => self.eof = true,
+                    Poll::Ready(Ok(n)) => rdlen += n,
+                    Poll::Pending => {
+                        read_would_block = true;
+                        break;
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                }
+            }
+
+            return match (self.eof, self.session.is_handshaking()) {
+                (true, true) => {
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof, "tls handshake eof");
+                    Poll::Ready(Err(err))
+                }
+                (_, false) => Poll::Ready(Ok((rdlen, wrlen))),
+                (_, true) if write_would_block || read_would_block => {
+                    if rdlen != 0 || wrlen != 0 {
+                        Poll::Ready(Ok((rdlen, wrlen)))
+                    } else {
+                        Poll::Pending
+                    }
+                }
+                (..) => continue,
+            };
+        }
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite + Unpin, C, SD> AsyncRead for Stream<'a, IO, C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+    SD: SideData,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        if self.lazy {
+            ready!(self.poll_lazy_handshake(cx))?;
+        }
+
+        let mut io_pending = false;
+
+        // read a packet
+        while !self.eof && self.session.wants_read() {
+            match self.read_io(cx) {
+                Poll::Ready(Ok(0)) => {
+                    self.eof = true;
+                    break;
+                }
+                Poll::Ready(Ok(_)) => (),
+                Poll::Pending => {
+                    io_pending = true;
+                    break;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            }
+        }
+
+        match self.session.reader().read(buf.initialize_unfilled()) {
+            Ok(n) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if !io_pending {
+                    // If `wants_read()` returns true but we actually read zero bytes,
+                    // wake up to avoid leaving the task parked forever.
+                    cx.waker().wake_by_ref();
+                }
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite + Unpin, C, SD> AsyncWrite for Stream<'a, IO, C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+    SD: SideData,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.lazy {
+            ready!(self.poll_lazy_handshake(cx))?;
+        }
+
+        if let Some(limit) = self.coalesce_limit {
+            return self.poll_write_coalesced(cx, buf, limit);
+        }
+
+        let mut pos = 0;
+
+        while pos != buf.len() {
+            let mut would_block = false;
+
+            match self.session.writer().write(&buf[pos..]) {
+                Ok(n) => pos += n,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+
+            while self.session.wants_write() {
+                match self.write_io(cx) {
+                    Poll::Ready(Ok(0)) | Poll::Pending => {
+                        would_block = true;
+                        break;
+                    }
+                    Poll::Ready(Ok(_)) => (),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                }
+            }
+
+            return match (pos, would_block) {
+                (0, true) => Poll::Pending,
+                (n, true) => Poll::Ready(Ok(n)),
+                (_, false) => continue,
+            };
+        }
+
+        Poll::Ready(Ok(pos))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.lazy {
+            ready!(self.poll_lazy_handshake(cx))?;
+        }
+
+        // When coalescing is on, fold the first non-empty slice through the
+        // shared buffer; the `AsyncWrite` contract allows a short write.
+        if let Some(limit) = self.coalesce_limit {
+            return match bufs.iter().find(|buf| !buf.is_empty()) {
+                Some(buf) => self.poll_write_coalesced(cx, buf, limit),
+                None => Poll::Ready(Ok(0)),
+            };
+        }
+
+        // rustls' `Writer` does not override `write_vectored`, so handing it
+        // `bufs` directly would take only the first slice and emit one record
+        // per call. Gather the slices so a single vectored write lands in one
+        // record, matching the non-vectored `poll_write` loop otherwise.
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Poll::Ready(Ok(0));
+        }
+        let mut joined = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+        for buf in bufs {
+            joined.extend_from_slice(buf);
+        }
+
+        let mut pos = 0;
+
+        while pos != joined.len() {
+            let mut would_block = false;
+
+            match self.session.writer().write(&joined[pos..]) {
+                Ok(n) => pos += n,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+
+            while self.session.wants_write() {
+                match self.write_io(cx) {
+                    Poll::Ready(Ok(0)) | Poll::Pending => {
+                        would_block = true;
+                        break;
+                    }
+                    Poll::Ready(Ok(_)) => (),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                }
+            }
+
+            return match (pos, would_block) {
+                (0, true) => Poll::Pending,
+                (n, true) => Poll::Ready(Ok(n)),
+                (_, false) => continue,
+            };
+        }
+
+        Poll::Ready(Ok(pos))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.coalesce_limit.is_some() {
+            ready!(self.flush_coalesced(cx))?;
+        }
+        self.session.writer().flush()?;
+        while self.session.wants_write() {
+            ready!(self.write_io(cx))?;
+        }
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.coalesce_limit.is_some() {
+            ready!(self.flush_coalesced(cx))?;
+        }
+        while self.session.wants_write() {
+            ready!(self.write_io(cx))?;
+        }
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// A transport that is either plaintext or wrapped in a server-side TLS session.
+///
+/// A server that speaks both `http://` and `https://` on the same accept loop
+/// would otherwise have to erase the concrete stream type behind a
+/// `Box<dyn AsyncRead + AsyncWrite>` and branch on it by hand. `Encryption`
+/// does that dispatch instead: [`Encryption::new`] yields the plaintext variant
+/// when no [`ServerConfig`] is given and otherwise holds a [`ServerConnection`]
+/// whose handshake is driven transparently on the first read or write.
+pub enum Encryption<IO> {
+    /// An unencrypted stream.
+    Plain(IO),
+    /// A TLS stream together with the server session that drives it and the
+    /// shutdown bookkeeping that keeps `close_notify` from being sent twice.
+    Tls(IO, ServerConnection, TlsState),
+}
+
+impl<IO> Encryption<IO> {
+    /// Wraps `io`, performing the TLS handshake lazily when `config` is given.
+    pub fn new(io: IO, config: Option<Arc<ServerConfig>>) -> io::Result<Self> {
+        match config {
+            Some(config) => {
+                let session = ServerConnection::new(config)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(Encryption::Tls(io, session, TlsState::Handshaking))
+            }
+            None => Ok(Encryption::Plain(io)),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for Encryption<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Encryption::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            Encryption::Tls(io, session, _) => {
+                let mut stream = Stream::new(io, session);
+                while stream.session.is_handshaking() {
+                    ready!(stream.handshake(cx))?;
+                }
+                stream.as_mut_pin().poll_read(cx, buf)
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Encryption<IO> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Encryption::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            Encryption::Tls(io, session, _) => {
+                let mut stream = Stream::new(io, session);
+                while stream.session.is_handshaking() {
+                    ready!(stream.handshake(cx))?;
+                }
+                stream.as_mut_pin().poll_write(cx, buf)
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Encryption::Plain(io) => Pin::new(io).poll_flush(cx),
+            Encryption::Tls(io, session, _) => Stream::new(io, session).as_mut_pin().poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Encryption::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            Encryption::Tls(io, session, state) => {
+                // Emit a `close_notify` once so the peer can tell a clean close
+                // apart from a truncation attack, mirroring the TlsStream
+                // shutdown paths; `state` guards against a second send on a
+                // re-polled shutdown.
+                if state.writeable() {
+                    session.send_close_notify();
+                    state.shutdown_write();
+                }
+
+                Stream::new(io, session).as_mut_pin().poll_shutdown(cx)
+            }
+        }
+    }
+}
+
+/// An adapter that exposes a synchronous [`Read`] over an [`AsyncRead`] and its
+/// [`Context`], turning `Poll::Pending` into [`io::ErrorKind::WouldBlock`].
+pub struct SyncReadAdapter<'a, 'b, T> {
+    pub io: &'a mut T,
+    pub cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, T: AsyncRead + Unpin> Read for SyncReadAdapter<'a, 'b, T> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(err)) => Err(err),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// An adapter that exposes a synchronous [`Write`] over an [`AsyncWrite`] and
+/// its [`Context`], turning `Poll::Pending` into [`io::ErrorKind::WouldBlock`].
+pub struct SyncWriteAdapter<'a, 'b, T> {
+    pub io: &'a mut T,
+    pub cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, T: AsyncWrite + Unpin> Write for SyncWriteAdapter<'a, 'b, T> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
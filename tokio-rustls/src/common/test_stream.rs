@@ -227,6 +227,33 @@ async fn stream_eof() -> io::Result<()> {
     Ok(()) as io::Result<()>
 }
 
+#[tokio::test]
+async fn stream_coalesce_write() -> io::Result<()> {
+    let (mut server, mut client) = make_pair();
+    poll_fn(|cx| do_handshake(&mut client, &mut server, cx)).await?;
+
+    {
+        let mut good = Good(&mut server);
+        let mut stream = Stream::new(&mut good, &mut client).set_coalesce_limit(Some(8));
+
+        // Spans the coalescing bound so the buffer both fills once and retains
+        // a tail that only the final flush emits.
+        stream.write_all(b"Hello World!").await?;
+        stream.flush().await?;
+        stream.session.send_close_notify();
+        stream.shutdown().await?;
+    }
+
+    let mut buf = String::new();
+    server
+        .process_new_packets()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    server.reader().read_to_string(&mut buf)?;
+    assert_eq!(buf, "Hello World!");
+
+    Ok(()) as io::Result<()>
+}
+
 fn make_pair() -> (ServerConnection, ClientConnection) {
     use std::convert::TryFrom;
 
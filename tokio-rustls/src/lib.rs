@@ -0,0 +1,182 @@
+//! Asynchronous TLS/SSL streams for Tokio using [Rustls](https://github.com/rustls/rustls).
+
+macro_rules! ready {
+    ( $e:expr ) => {
+        match $e {
+            ::std::task::Poll::Ready(t) => t,
+            ::std::task::Poll::Pending => return ::std::task::Poll::Pending,
+        }
+    };
+}
+
+mod common;
+
+pub mod client;
+pub mod server;
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use common::handshake::MidHandshake;
+use common::TlsState;
+
+pub use common::Encryption;
+
+/// A wrapper around a [`rustls::ClientConfig`], providing an async `connect`
+/// method that drives the TLS handshake to completion.
+#[derive(Clone)]
+pub struct TlsConnector {
+    inner: Arc<ClientConfig>,
+    lazy: bool,
+}
+
+impl From<Arc<ClientConfig>> for TlsConnector {
+    fn from(inner: Arc<ClientConfig>) -> TlsConnector {
+        TlsConnector { inner, lazy: false }
+    }
+}
+
+impl TlsConnector {
+    /// Connects to a server, driving the client handshake to completion (or,
+    /// with [`lazy`](Self::lazy) enabled, deferring it to the first read or
+    /// write).
+    #[inline]
+    pub fn connect<IO>(&self, domain: ServerName, stream: IO) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connect_with(domain, stream, |_| ())
+    }
+
+    /// Like [`connect`](Self::connect), but runs `f` against the freshly
+    /// created [`ClientConnection`] before the handshake begins.
+    pub fn connect_with<IO, F>(&self, domain: ServerName, stream: IO, f: F) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(&mut ClientConnection),
+    {
+        let mut session = match ClientConnection::new(self.inner.clone(), domain) {
+            Ok(session) => session,
+            Err(error) => {
+                return Connect(MidHandshake::Error(io::Error::new(
+                    io::ErrorKind::Other,
+                    error,
+                )));
+            }
+        };
+        f(&mut session);
+
+        Connect(MidHandshake::Handshaking(client::TlsStream {
+            io: stream,
+            state: TlsState::Handshaking,
+            lazy: self.lazy,
+            coalesce_limit: None,
+            write_buf: Vec::new(),
+            session,
+        }))
+    }
+
+    /// Controls whether the handshake is deferred until the first read or
+    /// write rather than being driven eagerly by the returned future.
+    pub fn lazy(&mut self, enabled: bool) -> &mut Self {
+        self.lazy = enabled;
+        self
+    }
+}
+
+/// A wrapper around a [`rustls::ServerConfig`], providing an async `accept`
+/// method that drives the TLS handshake to completion.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: Arc<ServerConfig>,
+    lazy: bool,
+}
+
+impl From<Arc<ServerConfig>> for TlsAcceptor {
+    fn from(inner: Arc<ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor { inner, lazy: false }
+    }
+}
+
+impl TlsAcceptor {
+    /// Accepts a connection, driving the server handshake to completion (or,
+    /// with [`lazy`](Self::lazy) enabled, deferring it to the first read or
+    /// write).
+    #[inline]
+    pub fn accept<IO>(&self, stream: IO) -> Accept<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.accept_with(stream, |_| ())
+    }
+
+    /// Like [`accept`](Self::accept), but runs `f` against the freshly created
+    /// [`ServerConnection`] before the handshake begins.
+    pub fn accept_with<IO, F>(&self, stream: IO, f: F) -> Accept<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(&mut ServerConnection),
+    {
+        let mut session = match ServerConnection::new(self.inner.clone()) {
+            Ok(session) => session,
+            Err(error) => {
+                return Accept(MidHandshake::Error(io::Error::new(
+                    io::ErrorKind::Other,
+                    error,
+                )));
+            }
+        };
+        f(&mut session);
+
+        Accept(MidHandshake::Handshaking(server::TlsStream {
+            io: stream,
+            state: TlsState::Handshaking,
+            lazy: self.lazy,
+            coalesce_limit: None,
+            write_buf: Vec::new(),
+            session,
+        }))
+    }
+
+    /// Controls whether the handshake is deferred until the first read or
+    /// write, avoiding handshake-buffer allocation for connections that are
+    /// opened but never send data.
+    pub fn lazy(&mut self, enabled: bool) -> &mut Self {
+        self.lazy = enabled;
+        self
+    }
+}
+
+/// Future returned from [`TlsConnector::connect`] which resolves to a ready
+/// [`client::TlsStream`] once the handshake completes, or at once when the
+/// handshake is deferred.
+pub struct Connect<IO>(MidHandshake<client::TlsStream<IO>>);
+
+/// Future returned from [`TlsAcceptor::accept`] which resolves to a ready
+/// [`server::TlsStream`] once the handshake completes, or at once when the
+/// handshake is deferred.
+pub struct Accept<IO>(MidHandshake<server::TlsStream<IO>>);
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Future for Connect<IO> {
+    type Output = io::Result<client::TlsStream<IO>>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().0).poll(cx)
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Future for Accept<IO> {
+    type Output = io::Result<server::TlsStream<IO>>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().0).poll(cx)
+    }
+}